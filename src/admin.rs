@@ -0,0 +1,324 @@
+// Authenticated admin HTTP+JSON API for inspecting and mutating `MacControlMap` /
+// `IP_BLOCKER` at runtime, since the DB-backed state otherwise has no live management
+// surface short of restarting the process or editing SQLite directly.
+//
+// Routes (all require `Authorization: Bearer <ADMIN_TOKEN>`):
+//   GET    /mac/:id        -> one entry, memory first then DB
+//   GET    /macs           -> full dump of the DB rows, overlaid with the in-memory map
+//   DELETE /mac/:id        -> evict from memory and DB
+//   POST   /mac            -> bind {mac_id, allowed_id} (same path as `update_pk`)
+//   POST   /ipblock/clear?ip=1.2.3.4 -> drop from IP_BLOCKER and reset IP_CHANGES
+use crate::credential::AuthProof;
+use crate::mac_control::{LockMacControl, MacControlMap, IP_BLOCKER, IP_CHANGES};
+use hbb_common::{
+    log,
+    rendezvous_proto::register_pk_response,
+    tokio::net::{TcpListener, TcpStream},
+    ResultType,
+};
+use serde_derive::Serialize;
+use std::net::SocketAddr;
+
+#[derive(Debug, Serialize)]
+struct MacDump {
+    mac_id: String,
+    allowed_id: String,
+}
+
+impl MacDump {
+    async fn from_lock(lock: &LockMacControl) -> Self {
+        let r = lock.read().await;
+        Self {
+            mac_id: r.mac_id.clone(),
+            allowed_id: r.allowed_id.clone(),
+        }
+    }
+}
+
+fn admin_token() -> Option<String> {
+    std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty())
+}
+
+fn is_authorized(headers: &str) -> bool {
+    let expected = match admin_token() {
+        Some(t) => t,
+        // No token configured: refuse everything rather than silently running open.
+        None => return false,
+    };
+    headers
+        .lines()
+        .find_map(|l| l.strip_prefix("Authorization: Bearer "))
+        .map(|got| got.trim() == expected)
+        .unwrap_or(false)
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: String,
+}
+
+// Bound on the request body this admin API will ever allocate a buffer for. The only
+// route with a body is `POST /mac`, whose JSON payload is two short strings, so this is
+// generous headroom without letting an unauthenticated caller force a large allocation.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+// Bound on the request line plus the full header block this admin API will ever buffer.
+// Applied before `is_authorized` runs, since that check itself needs the header block --
+// an unauthenticated caller must not be able to force unbounded growth here just by
+// never sending the blank line that ends the headers.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+// Read a single line capped at `budget_remaining` bytes (including anything already read for
+// this request), erroring out instead of growing without bound if the peer never sends
+// `\n` within that budget.
+async fn read_line_capped<R: hbb_common::tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+    budget_remaining: usize,
+) -> ResultType<usize> {
+    use hbb_common::tokio::io::AsyncBufReadExt;
+    let n = reader.take(budget_remaining as u64).read_line(line).await?;
+    if n == budget_remaining && !line.ends_with('\n') {
+        return Err(hbb_common::anyhow::anyhow!(
+            "request line exceeded {} byte budget",
+            budget_remaining
+        ));
+    }
+    Ok(n)
+}
+
+async fn read_request(stream: &mut TcpStream) -> ResultType<(Request, bool)> {
+    use hbb_common::tokio::io::{AsyncReadExt, BufReader};
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    let mut used = read_line_capped(&mut reader, &mut request_line, MAX_HEADER_BYTES).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_owned();
+    let full_path = parts.next().unwrap_or("/").to_owned();
+    let (path, query) = match full_path.split_once('?') {
+        Some((p, q)) => (p.to_owned(), q.to_owned()),
+        None => (full_path, String::new()),
+    };
+
+    let mut header_block = String::new();
+    let mut content_length = 0usize;
+    loop {
+        let mut line = String::new();
+        let remaining = MAX_HEADER_BYTES.saturating_sub(used);
+        if remaining == 0 {
+            return Err(hbb_common::anyhow::anyhow!(
+                "request headers exceeded {} byte budget",
+                MAX_HEADER_BYTES
+            ));
+        }
+        let n = read_line_capped(&mut reader, &mut line, remaining).await?;
+        used += n;
+        if n == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length: ") {
+            content_length = v.trim().parse().unwrap_or(0);
+        }
+        header_block.push_str(&line);
+    }
+    let authorized = is_authorized(&header_block);
+
+    // Check auth and cap the declared size before allocating/reading anything: an
+    // unauthenticated caller must not be able to force a large buffer just by lying about
+    // Content-Length.
+    if !authorized {
+        return Ok((
+            Request {
+                method,
+                path,
+                query,
+                body: String::new(),
+            },
+            false,
+        ));
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(hbb_common::anyhow::anyhow!(
+            "request body too large: {} > {}",
+            content_length,
+            MAX_BODY_BYTES
+        ));
+    }
+
+    let mut body = vec![0u8; content_length];
+    if content_length > 0 {
+        reader.read_exact(&mut body).await?;
+    }
+    Ok((
+        Request {
+            method,
+            path,
+            query,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        },
+        authorized,
+    ))
+}
+
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .find_map(|kv| kv.strip_prefix(key).and_then(|v| v.strip_prefix('=')))
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    body: String,
+) -> ResultType<()> {
+    use hbb_common::tokio::io::AsyncWriteExt;
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.as_bytes().len(),
+        body
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    Ok(())
+}
+
+async fn handle(mcm: MacControlMap, mut stream: TcpStream) -> ResultType<()> {
+    let (req, authorized) = read_request(&mut stream).await?;
+    if !authorized {
+        return write_response(&mut stream, "401 Unauthorized", "{\"error\":\"unauthorized\"}".into()).await;
+    }
+
+    if req.method == "POST" {
+        if let Some(mac_id) = req.path.strip_prefix("/mac/").and_then(|s| s.strip_suffix("/challenge")) {
+            return match mcm.request_challenge(mac_id).await {
+                Some(nonce) => {
+                    write_response(&mut stream, "200 OK", format!("{{\"nonce\":{:?}}}", nonce)).await
+                }
+                None => {
+                    write_response(&mut stream, "404 Not Found", "{\"error\":\"not enrolled\"}".into()).await
+                }
+            };
+        }
+        if let Some(mac_id) = req.path.strip_prefix("/mac/").and_then(|s| s.strip_suffix("/enroll")) {
+            #[derive(serde_derive::Deserialize)]
+            struct Enroll {
+                enrollment_token: String,
+                secret: String,
+            }
+            let enroll: Enroll = match serde_json::from_str(&req.body) {
+                Ok(e) => e,
+                Err(_) => {
+                    return write_response(&mut stream, "400 Bad Request", "{\"error\":\"bad body\"}".into())
+                        .await
+                }
+            };
+            return match mcm.enroll(mac_id, &enroll.enrollment_token, &enroll.secret).await {
+                Ok(salt) => {
+                    write_response(&mut stream, "200 OK", format!("{{\"salt\":{:?}}}", salt)).await
+                }
+                Err(_) => {
+                    write_response(&mut stream, "409 Conflict", "{\"error\":\"enroll failed\"}".into()).await
+                }
+            };
+        }
+    }
+
+    let path_id = req.path.strip_prefix("/mac/").map(|s| s.to_owned());
+    match (req.method.as_str(), req.path.as_str(), path_id) {
+        ("GET", _, Some(mac_id)) => match mcm.get(&mac_id).await {
+            Some(lock) => {
+                let dump = MacDump::from_lock(&lock).await;
+                write_response(&mut stream, "200 OK", serde_json::to_string(&dump)?).await
+            }
+            None => write_response(&mut stream, "404 Not Found", "{\"error\":\"not found\"}".into()).await,
+        },
+        ("DELETE", _, Some(mac_id)) => {
+            mcm.evict(&mac_id).await;
+            write_response(&mut stream, "200 OK", "{\"ok\":true}".into()).await
+        }
+        ("GET", "/macs", _) => {
+            let dump = mcm.dump_all().await;
+            write_response(&mut stream, "200 OK", serde_json::to_string(&dump)?).await
+        }
+        ("POST", "/mac", _) => {
+            #[derive(serde_derive::Deserialize)]
+            struct Bind {
+                mac_id: String,
+                allowed_id: String,
+            }
+            let bind: Bind = match serde_json::from_str(&req.body) {
+                Ok(b) => b,
+                Err(_) => {
+                    return write_response(&mut stream, "400 Bad Request", "{\"error\":\"bad body\"}".into())
+                        .await
+                }
+            };
+            let lock = mcm.get_or(&bind.mac_id).await;
+            let addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+            let mut mcm = mcm.clone();
+            let result = mcm
+                .update_pk(bind.mac_id, bind.allowed_id, lock, addr, AuthProof::Trusted)
+                .await;
+            let ok = result == register_pk_response::Result::OK;
+            write_response(
+                &mut stream,
+                if ok { "200 OK" } else { "409 Conflict" },
+                format!("{{\"ok\":{}}}", ok),
+            )
+            .await
+        }
+        ("POST", "/ipblock/clear", _) => {
+            let ip = query_param(&req.query, "ip").unwrap_or("").to_owned();
+            if ip.is_empty() {
+                return write_response(&mut stream, "400 Bad Request", "{\"error\":\"missing ip\"}".into())
+                    .await;
+            }
+            IP_BLOCKER.lock().await.remove(&ip);
+            IP_CHANGES.lock().await.remove(&ip);
+            write_response(&mut stream, "200 OK", "{\"ok\":true}".into()).await
+        }
+        _ => write_response(&mut stream, "404 Not Found", "{\"error\":\"no route\"}".into()).await,
+    }
+}
+
+// Start the admin HTTP server on `ADMIN_PORT` (disabled when unset). Each connection is
+// handled on its own task; this is an operator-facing surface, not a hot path.
+pub(crate) async fn start(mcm: MacControlMap) {
+    let port = match std::env::var("ADMIN_PORT").ok().and_then(|p| p.parse::<u16>().ok()) {
+        Some(p) => p,
+        None => {
+            log::info!("admin: ADMIN_PORT not set, admin API disabled");
+            return;
+        }
+    };
+    if admin_token().is_none() {
+        log::warn!("admin: ADMIN_TOKEN not set, admin API disabled");
+        return;
+    }
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(err) => {
+            log::error!("admin: failed to bind {}: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("admin: listening on {}", addr);
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(err) => {
+                log::error!("admin: accept failed: {}", err);
+                continue;
+            }
+        };
+        let mcm = mcm.clone();
+        hbb_common::tokio::spawn(async move {
+            if let Err(err) = handle(mcm, stream).await {
+                log::debug!("admin: request from {} failed: {}", peer_addr, err);
+            }
+        });
+    }
+}
@@ -0,0 +1,300 @@
+use crate::mac_control::MacControlMap;
+use hbb_common::{
+    log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+// Lamport-clock version stamp attached to every replicated `MacControl` row.
+// Conflicts are resolved last-write-wins: higher `lamport` wins, `node_id` breaks ties.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct VersionStamp {
+    pub(crate) lamport: u64,
+    pub(crate) node_id: u32,
+}
+
+impl VersionStamp {
+    #[inline]
+    pub(crate) fn is_newer_than(&self, other: &VersionStamp) -> bool {
+        (self.lamport, self.node_id) > (other.lamport, other.node_id)
+    }
+}
+
+// One replicated `mac_id -> allowed_id` row together with its version stamp, as pushed
+// to peers and exchanged during anti-entropy.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct ReplicatedEntry {
+    pub(crate) mac_id: String,
+    pub(crate) allowed_id: String,
+    pub(crate) version: VersionStamp,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum GossipMsg {
+    // node_id -> max lamport counter this node has locally observed
+    Digest(HashMap<u32, u64>),
+    // entries the sender believes the requester is missing, keyed by its digest reply
+    Entries(Vec<ReplicatedEntry>),
+    // ask a peer for everything it has past `since` for each node_id in the map
+    Pull(HashMap<u32, u64>),
+    // ip -> (node_id -> hit count), pushed whenever a node bumps its local IP_BLOCKER
+    // counter. CRDT grow-only counter: merged by taking the max per (ip, node_id).
+    Counters(HashMap<String, HashMap<u32, u32>>),
+}
+
+pub(crate) fn local_node_id() -> u32 {
+    static NODE_ID: AtomicU64 = AtomicU64::new(0);
+    let cur = NODE_ID.load(Ordering::Relaxed);
+    if cur != 0 {
+        return cur as u32;
+    }
+    let id = std::env::var("NODE_ID")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or_else(|| std::process::id());
+    NODE_ID.store(id as u64, Ordering::Relaxed);
+    id
+}
+
+lazy_static::lazy_static! {
+    // highest lamport counter seen locally, used to stamp the next local mutation
+    static ref LOCAL_COUNTER: Arc<std::sync::atomic::AtomicU64> = Arc::new(std::sync::atomic::AtomicU64::new(0));
+}
+
+#[inline]
+pub(crate) fn next_version() -> VersionStamp {
+    let lamport = LOCAL_COUNTER.fetch_add(1, Ordering::SeqCst) + 1;
+    VersionStamp {
+        lamport,
+        node_id: local_node_id(),
+    }
+}
+
+#[inline]
+pub(crate) fn observe_version(v: &VersionStamp) {
+    let mut cur = LOCAL_COUNTER.load(Ordering::SeqCst);
+    while v.lamport > cur {
+        match LOCAL_COUNTER.compare_exchange_weak(
+            cur,
+            v.lamport,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => break,
+            Err(actual) => cur = actual,
+        }
+    }
+}
+
+fn peers_from_env() -> Vec<String> {
+    std::env::var("PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+async fn send_msg(stream: &mut TcpStream, msg: &GossipMsg) -> ResultType<()> {
+    let buf = serde_json::to_vec(msg)?;
+    stream.write_u32(buf.len() as u32).await?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn recv_msg(stream: &mut TcpStream) -> ResultType<GossipMsg> {
+    let len = stream.read_u32().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+async fn apply_entries(mcm: &MacControlMap, entries: Vec<ReplicatedEntry>) {
+    for e in entries {
+        observe_version(&e.version);
+        mcm.apply_replicated(e.mac_id, e.allowed_id, e.version).await;
+    }
+}
+
+// Push a single changed row to every configured peer. Best-effort: a peer that is
+// unreachable just gets caught up on the next anti-entropy round.
+pub(crate) async fn push_to_peers(entry: ReplicatedEntry) {
+    for peer in peers_from_env() {
+        let entry = entry.clone();
+        tokio::spawn(async move {
+            if let Ok(mut stream) = TcpStream::connect(&peer).await {
+                let _ = send_msg(&mut stream, &GossipMsg::Entries(vec![entry])).await;
+            }
+        });
+    }
+}
+
+// Push this node's updated per-node counter for `ip` to every peer, so IP_BLOCKER rate
+// limiting converges cluster-wide instead of staying local to whichever node saw the hit.
+pub(crate) async fn push_counter_to_peers(ip: String, per_node: HashMap<u32, u32>) {
+    let mut counters = HashMap::new();
+    counters.insert(ip, per_node);
+    for peer in peers_from_env() {
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            if let Ok(mut stream) = TcpStream::connect(&peer).await {
+                let _ = send_msg(&mut stream, &GossipMsg::Counters(counters)).await;
+            }
+        });
+    }
+}
+
+// Merge a remote node's counter contributions into our local `IP_BLOCKER_COUNTERS`. Taking
+// the max per (ip, node_id) is safe/idempotent since each node's own counter only grows.
+async fn merge_counters(remote: HashMap<String, HashMap<u32, u32>>) {
+    let mut counters = crate::mac_control::IP_BLOCKER_COUNTERS.lock().await;
+    for (ip, per_node) in remote {
+        let local = counters.entry(ip).or_insert_with(HashMap::new);
+        for (node_id, count) in per_node {
+            let entry = local.entry(node_id).or_insert(0);
+            if count > *entry {
+                *entry = count;
+            }
+        }
+    }
+}
+
+// Exchange a (node_id -> max counter) digest with one peer and pull anything it is
+// ahead on. Runs once per peer per anti-entropy tick. No skip optimization: comparing
+// only our own digest against its last-seen value would miss the case where the peer's
+// state has advanced while ours hasn't, so it could never re-sync from a quiet peer.
+async fn anti_entropy_with_peer(mcm: MacControlMap, peer: String) -> ResultType<()> {
+    let local_digest = mcm.digest().await;
+    let mut stream = TcpStream::connect(&peer).await?;
+    send_msg(&mut stream, &GossipMsg::Digest(local_digest)).await?;
+    if let GossipMsg::Entries(entries) = recv_msg(&mut stream).await? {
+        apply_entries(&mcm, entries).await;
+    }
+    Ok(())
+}
+
+// Periodic anti-entropy loop: every `ANTI_ENTROPY_SECS` reconcile with all configured peers.
+pub(crate) async fn start_anti_entropy(mcm: MacControlMap) {
+    const ANTI_ENTROPY_SECS: u64 = 30;
+    loop {
+        for peer in peers_from_env() {
+            let mcm = mcm.clone();
+            let peer = peer.clone();
+            tokio::spawn(async move {
+                if let Err(err) = anti_entropy_with_peer(mcm, peer.clone()).await {
+                    log::debug!("cluster: anti-entropy with {} failed: {}", peer, err);
+                }
+            });
+        }
+        tokio::time::sleep(Duration::from_secs(ANTI_ENTROPY_SECS)).await;
+    }
+}
+
+// On startup, do a full pull from the first reachable peer so a fresh node is caught up
+// before it starts serving registrations.
+pub(crate) async fn bootstrap(mcm: &MacControlMap) {
+    for peer in peers_from_env() {
+        match TcpStream::connect(&peer).await {
+            Ok(mut stream) => {
+                let empty_digest: HashMap<u32, u64> = Default::default();
+                if send_msg(&mut stream, &GossipMsg::Pull(empty_digest)).await.is_ok() {
+                    if let Ok(GossipMsg::Entries(entries)) = recv_msg(&mut stream).await {
+                        log::info!("cluster: bootstrapped {} entries from {}", entries.len(), peer);
+                        apply_entries(mcm, entries).await;
+                        return;
+                    }
+                }
+            }
+            Err(err) => log::debug!("cluster: bootstrap peer {} unreachable: {}", peer, err),
+        }
+    }
+}
+
+async fn handle_conn(mcm: MacControlMap, mut stream: TcpStream) -> ResultType<()> {
+    match recv_msg(&mut stream).await? {
+        GossipMsg::Digest(remote_digest) => {
+            let missing = mcm.entries_ahead_of(&remote_digest).await;
+            send_msg(&mut stream, &GossipMsg::Entries(missing)).await?;
+        }
+        GossipMsg::Pull(remote_digest) => {
+            let missing = mcm.entries_ahead_of(&remote_digest).await;
+            send_msg(&mut stream, &GossipMsg::Entries(missing)).await?;
+        }
+        GossipMsg::Entries(entries) => {
+            apply_entries(&mcm, entries).await;
+        }
+        GossipMsg::Counters(counters) => {
+            merge_counters(counters).await;
+        }
+    }
+    Ok(())
+}
+
+// Cluster listener: accepts digest exchanges, pulls, and pushed entries from peers.
+pub(crate) async fn start_listener(mcm: MacControlMap, addr: &str) -> ResultType<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("cluster: listening on {}", addr);
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let mcm = mcm.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_conn(mcm, stream).await {
+                log::debug!("cluster: connection from {} failed: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VersionStamp;
+
+    #[test]
+    fn higher_lamport_wins() {
+        let a = VersionStamp { lamport: 1, node_id: 5 };
+        let b = VersionStamp { lamport: 2, node_id: 1 };
+        assert!(b.is_newer_than(&a));
+        assert!(!a.is_newer_than(&b));
+    }
+
+    #[test]
+    fn tie_breaks_on_node_id() {
+        let a = VersionStamp { lamport: 3, node_id: 1 };
+        let b = VersionStamp { lamport: 3, node_id: 2 };
+        assert!(b.is_newer_than(&a));
+        assert!(!a.is_newer_than(&b));
+        assert!(!a.is_newer_than(&a));
+    }
+}
+
+// Entry point called from the rendezvous server's startup once `MacControlMap` is ready.
+// Returns immediately when `PEERS` is unset so single-node deployments are unaffected.
+pub(crate) async fn start(mcm: MacControlMap) {
+    if peers_from_env().is_empty() {
+        log::info!("cluster: PEERS not set, running single-node");
+        return;
+    }
+    bootstrap(&mcm).await;
+    let listen_addr =
+        std::env::var("CLUSTER_ADDR").unwrap_or_else(|_| "0.0.0.0:21118".to_owned());
+    let listener_mcm = mcm.clone();
+    tokio::spawn(async move {
+        if let Err(err) = start_listener(listener_mcm, &listen_addr).await {
+            log::error!("cluster: listener exited: {}", err);
+        }
+    });
+    start_anti_entropy(mcm).await;
+}
@@ -0,0 +1,227 @@
+use crate::mac_control::PrivilegesBuf;
+use hbb_common::ResultType;
+use sqlx::{sqlite::SqlitePoolOptions, Row, SqlitePool};
+
+// A `mac_control` row as read back from storage.
+pub(crate) struct MacRow {
+    pub(crate) mac_id: String,
+    pub(crate) allowed_id: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct Database {
+    pool: SqlitePool,
+}
+
+impl Database {
+    pub(crate) async fn new(url: &str) -> ResultType<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(&format!("sqlite://{}?mode=rwc", url))
+            .await?;
+        let db = Self { pool };
+        db.create_tables().await?;
+        Ok(db)
+    }
+
+    async fn create_tables(&self) -> ResultType<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS mac_control (
+                mac_id TEXT PRIMARY KEY,
+                allowed_id TEXT NOT NULL DEFAULT '',
+                lamport_counter INTEGER NOT NULL DEFAULT 0,
+                version_node_id INTEGER NOT NULL DEFAULT 0,
+                privileges TEXT,
+                credential_salt BLOB,
+                credential_hash BLOB
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn insert_mac(&self, mac_id: &str, allowed_id: &str) -> ResultType<()> {
+        sqlx::query(
+            "INSERT INTO mac_control (mac_id, allowed_id) VALUES (?1, ?2)
+             ON CONFLICT(mac_id) DO UPDATE SET allowed_id = excluded.allowed_id",
+        )
+        .bind(mac_id)
+        .bind(allowed_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn update_mac(&self, mac_id: &str, allowed_id: &str) -> ResultType<()> {
+        sqlx::query("UPDATE mac_control SET allowed_id = ?2 WHERE mac_id = ?1")
+            .bind(mac_id)
+            .bind(allowed_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn get_mac_id(&self, mac_id: &str) -> ResultType<Option<MacRow>> {
+        let row = sqlx::query("SELECT mac_id, allowed_id FROM mac_control WHERE mac_id = ?1")
+            .bind(mac_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|r| MacRow {
+            mac_id: r.get("mac_id"),
+            allowed_id: r.get("allowed_id"),
+        }))
+    }
+
+    pub(crate) async fn get_allowed_id_with_mac_id(
+        &self,
+        mac_id: &str,
+        allowed_id: &str,
+    ) -> ResultType<Option<MacRow>> {
+        let row = sqlx::query(
+            "SELECT mac_id, allowed_id FROM mac_control WHERE mac_id = ?1 AND allowed_id = ?2",
+        )
+        .bind(mac_id)
+        .bind(allowed_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| MacRow {
+            mac_id: r.get("mac_id"),
+            allowed_id: r.get("allowed_id"),
+        }))
+    }
+
+    // Persist the lamport version stamp alongside a row so a restarted node resumes gossip
+    // from where it left off instead of replaying its whole history as "new".
+    pub(crate) async fn update_mac_version(
+        &self,
+        mac_id: &str,
+        lamport: u64,
+        node_id: u32,
+    ) -> ResultType<()> {
+        sqlx::query(
+            "UPDATE mac_control SET lamport_counter = ?2, version_node_id = ?3 WHERE mac_id = ?1",
+        )
+        .bind(mac_id)
+        .bind(lamport as i64)
+        .bind(node_id as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn delete_mac(&self, mac_id: &str) -> ResultType<()> {
+        sqlx::query("DELETE FROM mac_control WHERE mac_id = ?1")
+            .bind(mac_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Every `mac_id -> allowed_id` row on disk, for the admin API's `GET /macs` dump (which
+    // must reflect the DB even for rows the in-memory cache hasn't lazily loaded yet).
+    pub(crate) async fn get_all_macs(&self) -> ResultType<Vec<MacRow>> {
+        let rows = sqlx::query("SELECT mac_id, allowed_id FROM mac_control")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| MacRow {
+                mac_id: r.get("mac_id"),
+                allowed_id: r.get("allowed_id"),
+            })
+            .collect())
+    }
+
+    // Rule set controlling which `allowed_id`s `mac_id` may bind, stored as serialized JSON
+    // in the `privileges` column (mirrors how `MacControlInfo` is already serde-serialized
+    // elsewhere in this crate).
+    pub(crate) async fn get_privileges(&self, mac_id: &str) -> ResultType<Option<PrivilegesBuf>> {
+        let row = sqlx::query("SELECT privileges FROM mac_control WHERE mac_id = ?1")
+            .bind(mac_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row
+            .and_then(|r| r.get::<Option<String>, _>("privileges"))
+            .and_then(|raw| serde_json::from_str(&raw).ok()))
+    }
+
+    pub(crate) async fn set_privileges(
+        &self,
+        mac_id: &str,
+        privileges: &PrivilegesBuf,
+    ) -> ResultType<()> {
+        let raw = serde_json::to_string(privileges)?;
+        sqlx::query("UPDATE mac_control SET privileges = ?2 WHERE mac_id = ?1")
+            .bind(mac_id)
+            .bind(raw)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // `(salt, salted_key)` for `mac_id`'s enrolled credential, if any. `salted_key` is the
+    // Argon2id output used directly as the HMAC key in the challenge/response handshake.
+    pub(crate) async fn get_credential(&self, mac_id: &str) -> ResultType<Option<(Vec<u8>, Vec<u8>)>> {
+        let row = sqlx::query(
+            "SELECT credential_salt, credential_hash FROM mac_control WHERE mac_id = ?1",
+        )
+        .bind(mac_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.and_then(|r| {
+            let salt: Option<Vec<u8>> = r.get("credential_salt");
+            let hash: Option<Vec<u8>> = r.get("credential_hash");
+            salt.zip(hash)
+        }))
+    }
+
+    // Upsert, not a bare UPDATE: first-time enrollment is exactly the case where `mac_id`
+    // has no row yet (nothing has called `insert_mac`/`update_mac` for it), so an UPDATE
+    // alone would silently affect 0 rows and `enroll()` would report success for nothing.
+    pub(crate) async fn set_credential(
+        &self,
+        mac_id: &str,
+        salt: &[u8],
+        salted_key: &[u8],
+    ) -> ResultType<()> {
+        sqlx::query(
+            "INSERT INTO mac_control (mac_id, credential_salt, credential_hash) VALUES (?1, ?2, ?3)
+             ON CONFLICT(mac_id) DO UPDATE SET credential_salt = excluded.credential_salt, credential_hash = excluded.credential_hash",
+        )
+        .bind(mac_id)
+        .bind(salt)
+        .bind(salted_key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Every row's replication state, for anti-entropy digests/pulls. Source of truth for
+    // `MacControlMap::digest`/`entries_ahead_of` so a fresh node's in-memory cache being
+    // empty doesn't make it look like it has nothing to offer peers.
+    pub(crate) async fn get_all_with_version(&self) -> ResultType<Vec<(MacRow, u64, u32)>> {
+        let rows = sqlx::query(
+            "SELECT mac_id, allowed_id, lamport_counter, version_node_id FROM mac_control",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                let lamport: i64 = r.get("lamport_counter");
+                let node_id: i64 = r.get("version_node_id");
+                (
+                    MacRow {
+                        mac_id: r.get("mac_id"),
+                        allowed_id: r.get("allowed_id"),
+                    },
+                    lamport as u64,
+                    node_id as u32,
+                )
+            })
+            .collect())
+    }
+}
@@ -1,4 +1,7 @@
+use crate::cluster::{self, ReplicatedEntry, VersionStamp};
+use crate::credential::{self, AuthProof};
 use crate::database;
+use crate::policy_config::{PolicyConfig, Tunables};
 use hbb_common::{
     log,
     rendezvous_proto::*,
@@ -6,20 +9,80 @@ use hbb_common::{
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, collections::HashSet, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Instant,
+};
 
 type IpBlockMap = HashMap<String, ((u32, Instant), (HashSet<String>, Instant))>;
 type UserStatusMap = HashMap<Vec<u8>, Arc<(Option<Vec<u8>>, bool)>>;
 type IpChangesMap = HashMap<String, (Instant, HashMap<String, i32>)>;
+// Per-node contribution to a blocked IP's hit count. Summed across nodes on read so the
+// block threshold applies cluster-wide even though each node only sees its own traffic.
+type IpBlockerCountersMap = HashMap<String, HashMap<u32, u32>>;
 lazy_static::lazy_static! {
     pub(crate) static ref IP_BLOCKER: Mutex<IpBlockMap> = Default::default();
     pub(crate) static ref USER_STATUS: RwLock<UserStatusMap> = Default::default();
     pub(crate) static ref IP_CHANGES: Mutex<IpChangesMap> = Default::default();
+    pub(crate) static ref IP_BLOCKER_COUNTERS: Mutex<IpBlockerCountersMap> = Default::default();
+    // ips pinned as always-allowed by the policy config's `ip_allow` list; never counted
+    // towards the IP_BLOCKER threshold.
+    pub(crate) static ref IP_ALLOWLIST: RwLock<HashSet<String>> = Default::default();
+}
+
+// Blocker window tunables. Atomic statics, read synchronously (no `.await`) through the
+// accessor functions below; `load_policy_config` overwrites their values from
+// `[tunables]` in the `CONFIG_PATH` file instead of them being recompile-only.
+pub static IP_CHANGE_DUR: AtomicU64 = AtomicU64::new(180);
+pub static IP_BLOCK_DUR: AtomicU64 = AtomicU64::new(60);
+pub static DAY_SECONDS: AtomicU64 = AtomicU64::new(3600 * 24);
+
+#[inline]
+pub fn ip_change_dur() -> u64 {
+    IP_CHANGE_DUR.load(Ordering::Relaxed)
+}
+
+#[inline]
+pub fn ip_change_dur_x2() -> u64 {
+    ip_change_dur() * 2
+}
+
+#[inline]
+pub fn ip_block_dur() -> u64 {
+    IP_BLOCK_DUR.load(Ordering::Relaxed)
+}
+
+#[inline]
+pub fn day_seconds() -> u64 {
+    DAY_SECONDS.load(Ordering::Relaxed)
+}
+
+// Record one hit against `ip` from this node and return the cluster-wide total (the sum of
+// every node's counter for that ip), so the block threshold is enforced consistently no
+// matter which node in the cluster is seeing the traffic. Allowlisted ips (from the policy
+// config's `ip_allow`) are never counted.
+pub(crate) async fn bump_ip_blocker_counter(ip: &str) -> u32 {
+    if is_ip_allowlisted(ip).await {
+        return 0;
+    }
+    let per_node = {
+        let mut counters = IP_BLOCKER_COUNTERS.lock().await;
+        let per_node = counters.entry(ip.to_owned()).or_insert_with(HashMap::new);
+        *per_node.entry(cluster::local_node_id()).or_insert(0) += 1;
+        per_node.clone()
+    };
+    let total = per_node.values().sum();
+    cluster::push_counter_to_peers(ip.to_owned(), per_node).await;
+    total
+}
+
+// Whether `ip` was pinned as always-allowed by the `ip_allow` list in the policy config.
+pub(crate) async fn is_ip_allowlisted(ip: &str) -> bool {
+    IP_ALLOWLIST.read().await.contains(ip)
 }
-pub static IP_CHANGE_DUR: u64 = 180;
-pub static IP_CHANGE_DUR_X2: u64 = IP_CHANGE_DUR * 2;
-pub static DAY_SECONDS: u64 = 3600 * 24;
-pub static IP_BLOCK_DUR: u64 = 60;
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub(crate) struct MacControlInfo {
@@ -27,10 +90,74 @@ pub(crate) struct MacControlInfo {
     pub(crate) ip: String,
 }
 
+// Whether a `mac_id` with no stored `PrivilegesBuf` (or an empty `allow` list) may bind any
+// `allowed_id`. Operators that want the old "any mac, any id" behaviour set
+// `MAC_CONTROL_PERMISSIVE=1`; the default is deny-all so a rule set must be opted into.
+fn permissive_default() -> bool {
+    std::env::var("MAC_CONTROL_PERMISSIVE")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+// A single id-matching rule. `Base` is an exact match, `Subtree` matches a dotted/prefixed
+// namespace (`org.acme.` matches `org.acme.laptop1`), `Wildcard` matches a glob (`acme-*`,
+// single `*` only).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum PermRule {
+    Base(String),
+    Subtree(String),
+    Wildcard(String),
+}
+
+impl PermRule {
+    fn matches(&self, allowed_id: &str) -> bool {
+        match self {
+            PermRule::Base(id) => id == allowed_id,
+            PermRule::Subtree(prefix) => allowed_id.starts_with(prefix.as_str()),
+            PermRule::Wildcard(pattern) => match pattern.split_once('*') {
+                Some((prefix, suffix)) => {
+                    allowed_id.starts_with(prefix)
+                        && allowed_id.ends_with(suffix)
+                        && allowed_id.len() >= prefix.len() + suffix.len()
+                }
+                None => pattern == allowed_id,
+            },
+        }
+    }
+}
+
+// Per-mac allow/deny rule set, serialized into the `privileges` DB column. Deny always wins
+// over allow; an empty `allow` denies everything unless `MAC_CONTROL_PERMISSIVE` is set.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct PrivilegesBuf {
+    #[serde(default)]
+    pub(crate) allow: Vec<PermRule>,
+    #[serde(default)]
+    pub(crate) deny: Vec<PermRule>,
+}
+
+impl PrivilegesBuf {
+    // Whether this rule set permits binding `allowed_id`.
+    pub(crate) fn permits(&self, allowed_id: &str) -> bool {
+        if self.deny.iter().any(|r| r.matches(allowed_id)) {
+            return false;
+        }
+        if self.allow.iter().any(|r| r.matches(allowed_id)) {
+            return true;
+        }
+        self.allow.is_empty() && permissive_default()
+    }
+}
+
 pub(crate) struct MacControl {
     pub(crate) socket_addr: SocketAddr,
     pub(crate) mac_id: String,
     pub(crate) allowed_id: String,
+    // Replication version stamp; bumped on every local mutation, compared on every
+    // remote one so anti-entropy can resolve conflicts last-write-wins.
+    pub(crate) version: VersionStamp,
+    // Which `allowed_id`s this mac is permitted to bind. Evaluated on every `update_pk`.
+    pub(crate) privileges: PrivilegesBuf,
 }
 
 impl Default for MacControl {
@@ -38,7 +165,9 @@ impl Default for MacControl {
         Self {
             socket_addr: "0.0.0.0:0".parse().unwrap(),
             mac_id: String::new(),
-            allowed_id: String::new()
+            allowed_id: String::new(),
+            version: VersionStamp::default(),
+            privileges: PrivilegesBuf::default(),
         }
     }
 }
@@ -72,9 +201,65 @@ impl MacControlMap {
             map: Default::default(),
             db: database::Database::new(&db).await?,
         };
+        mcm.load_policy_config().await?;
+        let cluster_mcm = mcm.clone();
+        hbb_common::tokio::spawn(async move {
+            cluster::start(cluster_mcm).await;
+        });
+        let admin_mcm = mcm.clone();
+        hbb_common::tokio::spawn(async move {
+            crate::admin::start(admin_mcm).await;
+        });
         Ok(mcm)
     }
 
+    // Parse the `CONFIG_PATH` policy file (if set) and seed the in-memory map / tunables
+    // from it. DB-backed dynamic registrations still take precedence for any `mac_id` not
+    // pinned here, so this only establishes the baseline.
+    async fn load_policy_config(&self) -> ResultType<()> {
+        let cfg = PolicyConfig::load()?;
+        IP_CHANGE_DUR.store(cfg.tunables.ip_change_dur, Ordering::Relaxed);
+        IP_BLOCK_DUR.store(cfg.tunables.ip_block_dur, Ordering::Relaxed);
+        DAY_SECONDS.store(cfg.tunables.day_seconds, Ordering::Relaxed);
+        for ip in &cfg.ip_deny {
+            IP_BLOCKER
+                .lock()
+                .await
+                .entry(ip.clone())
+                .or_insert(((u32::MAX, Instant::now()), (HashSet::new(), Instant::now())));
+        }
+        if !cfg.ip_allow.is_empty() {
+            log::info!("policy_config: {} ip(s) allowlisted", cfg.ip_allow.len());
+        }
+        *IP_ALLOWLIST.write().await = cfg.ip_allow.iter().cloned().collect();
+        for binding in cfg.bindings_by_mac_id().into_values() {
+            if binding.mac_id.is_empty() {
+                continue;
+            }
+            let privileges = binding.privileges();
+            let mac_control = Arc::new(RwLock::new(MacControl {
+                mac_id: binding.mac_id.clone(),
+                allowed_id: binding.allowed_id.clone(),
+                privileges: privileges.clone(),
+                ..Default::default()
+            }));
+            self.map
+                .write()
+                .await
+                .insert(binding.mac_id.clone(), mac_control);
+            // Persist the baseline rule set too, so it's still in effect even once the
+            // mac_id is loaded straight from the DB on a later restart.
+            if let Err(err) = self.db.insert_mac(&binding.mac_id, &binding.allowed_id).await {
+                log::error!("db.insert_mac (policy binding) failed: {}", err);
+                continue;
+            }
+            if let Err(err) = self.db.set_privileges(&binding.mac_id, &privileges).await {
+                log::error!("db.set_privileges (policy binding) failed: {}", err);
+            }
+        }
+        Ok(())
+    }
+
     #[inline]
     pub(crate) async fn update_pk(
         &mut self,
@@ -82,16 +267,38 @@ impl MacControlMap {
         allowed_id: String,
         mac_control: LockMacControl,
         addr: SocketAddr,
+        auth: AuthProof,
     ) -> register_pk_response::Result {
         log::info!("mac update_pk {} {:?} {:?}", mac_id, addr, allowed_id);
-        let (mac) = {
+        match self.authenticate(&mac_id, &auth).await {
+            Ok(()) => {}
+            Err(result) => {
+                bump_ip_blocker_counter(&addr.ip().to_string()).await;
+                return result;
+            }
+        }
+        let version = cluster::next_version();
+        let (mac, privileges) = {
+            let r = mac_control.read().await;
+            (r.mac_id.clone(), r.privileges.clone())
+        };
+        if !privileges.permits(&allowed_id) {
+            log::warn!(
+                "mac update_pk denied: {} may not bind {}",
+                mac_id,
+                allowed_id
+            );
+            bump_ip_blocker_counter(&addr.ip().to_string()).await;
+            // No dedicated proto rejection variant for access-denied yet; NOT_EMPTY is the
+            // closest existing "can't register that id" result until the proto is extended.
+            return register_pk_response::Result::NOT_EMPTY;
+        }
+        {
             let mut w = mac_control.write().await;
             w.socket_addr = addr;
-            w.allowed_id = allowed_id;
-            (
-                w.mac_id.clone(),
-            )
-        };
+            w.allowed_id = allowed_id.clone();
+            w.version = version;
+        }
         if mac.is_empty() {
             match self.db.insert_mac(&mac_id, &allowed_id).await {
                 Err(err) => {
@@ -109,22 +316,172 @@ impl MacControlMap {
             }
             log::info!("mac updated instead of insert");
         }
+        if let Err(err) = self.db.update_mac_version(&mac_id, version.lamport, version.node_id).await {
+            log::error!("db.update_mac_version failed: {}", err);
+        }
+        cluster::push_to_peers(ReplicatedEntry {
+            mac_id,
+            allowed_id,
+            version,
+        })
+        .await;
         register_pk_response::Result::OK
     }
 
+    // Verify the caller actually holds the shared secret for `mac_id` before `update_pk`
+    // is allowed to touch its binding. A mac with no stored credential yet must go through
+    // `enroll` first; `update_pk` itself only ever sees a challenge response (or `Trusted`
+    // for the admin API, which has already authenticated the caller another way).
+    async fn authenticate(
+        &self,
+        mac_id: &str,
+        auth: &AuthProof,
+    ) -> Result<(), register_pk_response::Result> {
+        if matches!(auth, AuthProof::Trusted) {
+            return Ok(());
+        }
+        let AuthProof::Response(response) = auth else {
+            unreachable!("handled above");
+        };
+        let (_, salted_key) = match self.db.get_credential(mac_id).await.ok().flatten() {
+            Some(c) => c,
+            None => {
+                log::warn!("mac update_pk denied: {} has no enrolled credential", mac_id);
+                return Err(register_pk_response::Result::UUID_MISMATCH);
+            }
+        };
+        if credential::verify_response(mac_id, response, &salted_key).await {
+            Ok(())
+        } else {
+            log::warn!("mac update_pk denied: bad credential response for {}", mac_id);
+            Err(register_pk_response::Result::UUID_MISMATCH)
+        }
+    }
+
+    // First-time enrollment for `mac_id`: validate the operator-issued enrollment token,
+    // derive a fresh salted Argon2id key from `secret`, and persist `(salt, salted_key)`.
+    // Returns `salt` so the caller can hand it back to the device -- the device needs it
+    // to rederive the same salted key on every future challenge, but never needs to send
+    // `secret` again after this call.
+    pub(crate) async fn enroll(
+        &self,
+        mac_id: &str,
+        enrollment_token: &str,
+        secret: &str,
+    ) -> Result<Vec<u8>, register_pk_response::Result> {
+        if self.db.get_credential(mac_id).await.ok().flatten().is_some() {
+            log::warn!("mac enroll denied: {} already enrolled", mac_id);
+            return Err(register_pk_response::Result::UUID_MISMATCH);
+        }
+        if !credential::verify_enrollment_token(enrollment_token) {
+            log::warn!("mac enroll denied: bad enrollment token for {}", mac_id);
+            return Err(register_pk_response::Result::UUID_MISMATCH);
+        }
+        let salt = credential::generate_salt();
+        let salted_key = credential::derive_salted_key(secret, &salt).map_err(|err| {
+            log::error!("credential::derive_salted_key failed: {}", err);
+            register_pk_response::Result::SERVER_ERROR
+        })?;
+        if let Err(err) = self.db.set_credential(mac_id, &salt, &salted_key).await {
+            log::error!("db.set_credential failed: {}", err);
+            return Err(register_pk_response::Result::SERVER_ERROR);
+        }
+        Ok(salt)
+    }
+
+    // Issue a fresh challenge nonce for `mac_id`, the first step of the register
+    // handshake. `None` if `mac_id` hasn't enrolled a credential yet (it must `enroll`
+    // first).
+    pub(crate) async fn request_challenge(&self, mac_id: &str) -> Option<Vec<u8>> {
+        if self.db.get_credential(mac_id).await.ok().flatten().is_none() {
+            return None;
+        }
+        Some(credential::issue_challenge(mac_id).await)
+    }
+
+    // Apply a row received from a peer during anti-entropy or a push. Last-write-wins:
+    // only accepted if the incoming version is newer than what we already have.
+    pub(crate) async fn apply_replicated(
+        &self,
+        mac_id: String,
+        allowed_id: String,
+        version: VersionStamp,
+    ) {
+        let mac_control = self.get_or(&mac_id).await;
+        let mut w = mac_control.write().await;
+        if version.is_newer_than(&w.version) {
+            w.mac_id = mac_id.clone();
+            w.allowed_id = allowed_id.clone();
+            w.version = version;
+            drop(w);
+            if let Err(err) = self.db.update_mac(&mac_id, &allowed_id).await {
+                log::error!("db.update_mac (replicated) failed: {}", err);
+            }
+            if let Err(err) = self
+                .db
+                .update_mac_version(&mac_id, version.lamport, version.node_id)
+                .await
+            {
+                log::error!("db.update_mac_version (replicated) failed: {}", err);
+            }
+        }
+    }
+
+    // Compact digest of the highest version this node has seen per node_id, used to drive
+    // anti-entropy: a peer compares this against its own and replies with what we're missing.
+    // Built from the DB, not the lazily-populated in-memory cache, so a freshly started node
+    // (whose `map` is empty) still reports everything it actually has on disk.
+    pub(crate) async fn digest(&self) -> HashMap<u32, u64> {
+        let mut digest: HashMap<u32, u64> = HashMap::new();
+        let rows = self.db.get_all_with_version().await.unwrap_or_default();
+        for (_, lamport, node_id) in rows {
+            let entry = digest.entry(node_id).or_insert(0);
+            if lamport > *entry {
+                *entry = lamport;
+            }
+        }
+        digest
+    }
+
+    // All rows whose version is ahead of what `remote_digest` claims the peer has seen.
+    // Reads from the DB for the same reason as `digest`: the in-memory map is only a cache
+    // and must not be the thing that decides what a fresh node can offer peers.
+    pub(crate) async fn entries_ahead_of(
+        &self,
+        remote_digest: &HashMap<u32, u64>,
+    ) -> Vec<ReplicatedEntry> {
+        let rows = self.db.get_all_with_version().await.unwrap_or_default();
+        rows.into_iter()
+            .filter_map(|(row, lamport, node_id)| {
+                let remote_has = remote_digest.get(&node_id).copied().unwrap_or(0);
+                if lamport > remote_has {
+                    Some(ReplicatedEntry {
+                        mac_id: row.mac_id,
+                        allowed_id: row.allowed_id,
+                        version: VersionStamp { lamport, node_id },
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
     #[inline]
     pub(crate) async fn get(&self, mac_id: &String) -> Option<LockMacControl> {
         let p = self.map.read().await.get(mac_id).cloned();
         if p.is_some() {
             return p;
         } else if let Ok(Some(v)) = self.db.get_mac_id(mac_id).await {
+            let privileges = self.db.get_privileges(mac_id).await.ok().flatten().unwrap_or_default();
             let mac = MacControl {
                 mac_id: v.mac_id,
                 allowed_id: v.allowed_id,
+                privileges,
                 ..Default::default()
             };
             let mac_control = Arc::new(RwLock::new(mac));
-            self.map.write().await.insert(mac_id.to_owned(), mac.clone());
+            self.map.write().await.insert(mac_id.to_owned(), mac_control.clone());
             return Some(mac_control);
         }
         None
@@ -132,13 +489,15 @@ impl MacControlMap {
 
     pub(crate) async fn get_allowed_id_with_mac_id(&self, mac_id: &String, allowed_id: &String) -> Option<LockMacControl> {
         if let Ok(Some(v)) = self.db.get_allowed_id_with_mac_id(mac_id, allowed_id).await {
+            let privileges = self.db.get_privileges(mac_id).await.ok().flatten().unwrap_or_default();
             let mac = MacControl {
                 mac_id: v.mac_id,
                 allowed_id: v.allowed_id,
+                privileges,
                 ..Default::default()
             };
             let mac_control = Arc::new(RwLock::new(mac));
-            self.map.write().await.insert(mac_id.to_owned(), mac.clone());
+            self.map.write().await.insert(mac_id.to_owned(), mac_control.clone());
             return Some(mac_control);
         }
         None
@@ -167,4 +526,74 @@ impl MacControlMap {
     pub(crate) async fn is_in_memory(&self, mac_id: &String) -> bool {
         self.map.read().await.contains_key(mac_id)
     }
+
+    // Evict `mac_id` from both the in-memory map and the DB. Used by the admin API to
+    // revoke a wrongly-registered binding without a restart.
+    pub(crate) async fn evict(&self, mac_id: &str) {
+        self.map.write().await.remove(mac_id);
+        if let Err(err) = self.db.delete_mac(mac_id).await {
+            log::error!("db.delete_mac failed: {}", err);
+        }
+    }
+
+    // Full dump of `mac_id -> allowed_id`, for the admin API's `GET /macs`. Starts from the
+    // DB (the full dataset, including rows the in-memory cache hasn't lazily loaded yet)
+    // and then overlays whatever's in memory, since a just-written entry may not have
+    // reached disk-read visibility yet within this same connection pool.
+    pub(crate) async fn dump_all(&self) -> HashMap<String, String> {
+        let mut out: HashMap<String, String> = self
+            .db
+            .get_all_macs()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|row| (row.mac_id, row.allowed_id))
+            .collect();
+        for (mac_id, lock) in self.map.read().await.iter() {
+            out.insert(mac_id.clone(), lock.read().await.allowed_id.clone());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PermRule, PrivilegesBuf};
+
+    #[test]
+    fn base_matches_exact_only() {
+        let r = PermRule::Base("org.acme.laptop1".into());
+        assert!(r.matches("org.acme.laptop1"));
+        assert!(!r.matches("org.acme.laptop2"));
+    }
+
+    #[test]
+    fn subtree_matches_prefix() {
+        let r = PermRule::Subtree("org.acme.".into());
+        assert!(r.matches("org.acme.laptop1"));
+        assert!(!r.matches("org.other.laptop1"));
+    }
+
+    #[test]
+    fn wildcard_matches_glob() {
+        let r = PermRule::Wildcard("acme-*".into());
+        assert!(r.matches("acme-laptop1"));
+        assert!(!r.matches("other-laptop1"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let p = PrivilegesBuf {
+            allow: vec![PermRule::Subtree("org.acme.".into())],
+            deny: vec![PermRule::Base("org.acme.blocked".into())],
+        };
+        assert!(p.permits("org.acme.laptop1"));
+        assert!(!p.permits("org.acme.blocked"));
+    }
+
+    #[test]
+    fn empty_allow_denies_by_default() {
+        let p = PrivilegesBuf::default();
+        assert!(!p.permits("anything"));
+    }
 }
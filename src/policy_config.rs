@@ -0,0 +1,133 @@
+use crate::mac_control::PrivilegesBuf;
+use hbb_common::{log, ResultType};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+// Runtime-tunable blocker windows, overridable via the `[tunables]` table in the policy
+// config file. Falls back to the repo's historical defaults when unset.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct Tunables {
+    pub(crate) ip_change_dur: u64,
+    pub(crate) ip_block_dur: u64,
+    pub(crate) day_seconds: u64,
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Self {
+            ip_change_dur: 180,
+            ip_block_dur: 60,
+            day_seconds: 3600 * 24,
+        }
+    }
+}
+
+// A statically declared `mac_id -> allowed_id` binding plus its allow/deny rule set. Lets
+// operators pin a baseline policy that survives DB resets; dynamic DB registrations still
+// take precedence for any `mac_id` not listed here.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct MacBinding {
+    pub(crate) mac_id: String,
+    pub(crate) allowed_id: String,
+    #[serde(default)]
+    pub(crate) allow: Vec<crate::mac_control::PermRule>,
+    #[serde(default)]
+    pub(crate) deny: Vec<crate::mac_control::PermRule>,
+}
+
+impl MacBinding {
+    pub(crate) fn privileges(&self) -> PrivilegesBuf {
+        PrivilegesBuf {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+        }
+    }
+}
+
+// Top-level shape of the policy config file (TOML), loaded once at startup from the path
+// in `CONFIG_PATH`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct PolicyConfig {
+    #[serde(default)]
+    pub(crate) bindings: Vec<MacBinding>,
+    #[serde(default)]
+    pub(crate) ip_allow: Vec<String>,
+    #[serde(default)]
+    pub(crate) ip_deny: Vec<String>,
+    #[serde(default)]
+    pub(crate) tunables: Tunables,
+}
+
+impl PolicyConfig {
+    // Read and parse the config file at `CONFIG_PATH`. Returns the default (empty) config,
+    // unchanged behaviour, when the env var isn't set or the file doesn't exist.
+    pub(crate) fn load() -> ResultType<Self> {
+        let path = match std::env::var("CONFIG_PATH") {
+            Ok(p) => p,
+            Err(_) => return Ok(Self::default()),
+        };
+        let raw = match std::fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(err) => {
+                log::warn!("policy_config: could not read {}: {}", path, err);
+                return Ok(Self::default());
+            }
+        };
+        let cfg: Self = toml::from_str(&raw)?;
+        log::info!(
+            "policy_config: loaded {} binding(s) from {}",
+            cfg.bindings.len(),
+            path
+        );
+        Ok(cfg)
+    }
+
+    pub(crate) fn bindings_by_mac_id(&self) -> HashMap<String, MacBinding> {
+        self.bindings
+            .iter()
+            .map(|b| (b.mac_id.clone(), b.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_keeps_defaults() {
+        let cfg: PolicyConfig = toml::from_str("").unwrap();
+        assert_eq!(cfg.tunables.ip_change_dur, 180);
+        assert_eq!(cfg.tunables.ip_block_dur, 60);
+        assert!(cfg.bindings.is_empty());
+        assert!(cfg.ip_allow.is_empty());
+    }
+
+    #[test]
+    fn partial_tunables_fall_back_per_field() {
+        let cfg: PolicyConfig = toml::from_str("[tunables]\nip_block_dur = 120\n").unwrap();
+        assert_eq!(cfg.tunables.ip_block_dur, 120);
+        assert_eq!(cfg.tunables.ip_change_dur, 180);
+    }
+
+    #[test]
+    fn duplicate_mac_id_bindings_dedup_to_last() {
+        let cfg: PolicyConfig = toml::from_str(
+            r#"
+            [[bindings]]
+            mac_id = "m1"
+            allowed_id = "first"
+            [[bindings]]
+            mac_id = "m1"
+            allowed_id = "second"
+            "#,
+        )
+        .unwrap();
+        let by_id = cfg.bindings_by_mac_id();
+        assert_eq!(by_id.len(), 1);
+        assert_eq!(by_id["m1"].allowed_id, "second");
+    }
+}